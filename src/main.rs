@@ -1,10 +1,17 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io::Read;
+use std::io::Write;
 
-// Value of the memory array.
+// Default length of the memory tape, used when --tape-size is not given.
 const MEM_SIZE: usize = 3000;
 
+// Upper bound on the tape length, mirroring the way the external bytecode VM
+// caps its stack so a stray --tape-size can't ask for an unbounded allocation.
+const MAX_TAPE_SIZE: usize = 1 << 20;
+
 // Refer to https://esolangs.org/wiki/COW.
 // These are ordered to match that page.
 #[derive(Debug)] // needed for debug prints
@@ -35,12 +42,55 @@ enum Instruction {
     DecVal,
     IncVal,
     ZeroVal,
-    RegAccess, 
+    RegAccess,
     Write,
     Read,
     Loop(Vec<Instruction>),
 }
 
+// Flat, program-counter-friendly instruction stream produced by lowering the
+// nested Instruction tree. The recursive Loop node is replaced by a matched
+// LoopStart/LoopEnd pair, each carrying the index of its partner so the
+// executor can jump without scanning. This flat form is also what lets
+// ExecVal treat an arbitrary memory byte as an opcode at runtime.
+#[derive(Debug)] // needed for debug prints
+enum FlatInstruction {
+    DecPtr,
+    IncPtr,
+    ExecVal,
+    RWCond,
+    DecVal,
+    IncVal,
+    ZeroVal,
+    RegAccess,
+    Write,
+    Read,
+    LoopStart(usize), // index of the matching LoopEnd.
+    LoopEnd(usize), // index of the matching LoopStart.
+}
+
+impl FlatInstruction {
+    // Canonical COW opcode number for this instruction, matching the ordering
+    // on the esolangs page. Loop delimiters keep their spec numbers even though
+    // the executor handles their jumps directly rather than through dispatch.
+    fn opcode(&self) -> u8 {
+        match self {
+            FlatInstruction::LoopEnd(_) => 0,
+            FlatInstruction::DecPtr => 1,
+            FlatInstruction::IncPtr => 2,
+            FlatInstruction::ExecVal => 3,
+            FlatInstruction::RWCond => 4,
+            FlatInstruction::DecVal => 5,
+            FlatInstruction::IncVal => 6,
+            FlatInstruction::LoopStart(_) => 7,
+            FlatInstruction::ZeroVal => 8,
+            FlatInstruction::RegAccess => 9,
+            FlatInstruction::Write => 10,
+            FlatInstruction::Read => 11,
+        }
+    }
+}
+
 // COW register definition.
 struct Register {
     value: u8,
@@ -146,68 +196,471 @@ fn parse(commands: Vec<Command>) -> Vec<Instruction> {
     instructions
 }
 
-fn exec(instructions: &Vec<Instruction>, mem: &mut Vec<u8>, ptr: &mut usize, reg: &mut Register) {
+// Lower the nested Instruction tree into the flat instruction stream the
+// executor runs. Loop matching is resolved with a backpatching stack: when a
+// loop is opened we push a placeholder LoopStart and remember its index, then
+// on close we emit the LoopEnd pointing back at the start and patch the start
+// to point forward at the end.
+fn lower(instructions: &Vec<Instruction>, program: &mut Vec<FlatInstruction>) {
     for instr in instructions {
         match instr {
-            Instruction::DecPtr => *ptr -= 1,
-            Instruction::IncPtr =>  *ptr += 1,
-            Instruction::ExecVal => todo!(), // this requires refactoring to implement
-            Instruction::RWCond => {
-                // This should be refactored when ExecVal is fixed.
-                if mem[*ptr] == 0 {
-                    // Read just one byte.
-                    let mut buf: [u8; 1] = [0; 1];
-                    std::io::stdin().read_exact(&mut buf).expect("stdin read failed");
-                    mem[*ptr] = buf[0];
-                } else {
-                    print!("{}", mem[*ptr]);
-                }
+            Instruction::DecPtr => program.push(FlatInstruction::DecPtr),
+            Instruction::IncPtr => program.push(FlatInstruction::IncPtr),
+            Instruction::ExecVal => program.push(FlatInstruction::ExecVal),
+            Instruction::RWCond => program.push(FlatInstruction::RWCond),
+            Instruction::DecVal => program.push(FlatInstruction::DecVal),
+            Instruction::IncVal => program.push(FlatInstruction::IncVal),
+            Instruction::ZeroVal => program.push(FlatInstruction::ZeroVal),
+            Instruction::RegAccess => program.push(FlatInstruction::RegAccess),
+            Instruction::Write => program.push(FlatInstruction::Write),
+            Instruction::Read => program.push(FlatInstruction::Read),
+            Instruction::Loop(loop_instructions) => {
+                let start = program.len();
+                program.push(FlatInstruction::LoopStart(0)); // patched below.
+                lower(loop_instructions, program);
+                let end = program.len();
+                program.push(FlatInstruction::LoopEnd(start));
+                program[start] = FlatInstruction::LoopStart(end);
             }
-            Instruction::DecVal => mem[*ptr] = mem[*ptr].wrapping_sub(1u8),
-            Instruction::IncVal => mem[*ptr] = mem[*ptr].wrapping_add(1u8),
-            Instruction::ZeroVal => mem[*ptr] = 0,
-            Instruction::RegAccess => {
-                if reg.empty {
-                    reg.value = mem[*ptr];
-                } else {
-                    mem[*ptr] = reg.value;
-                }
-                
-                reg.empty = !reg.empty;
+        }
+    }
+}
+
+// Write the current memory block to STDOUT as a single raw byte. Going through
+// `as char` would re-encode values above 127 as multi-byte UTF-8, so write the
+// byte directly to stay character-accurate.
+fn write_byte(mem: &Vec<u8>, ptr: &usize) {
+    std::io::stdout().write_all(&[mem[*ptr]]).expect("stdout write failed");
+}
+
+// Read a single byte from STDIN into the current memory block.
+fn read_byte(mem: &mut Vec<u8>, ptr: &usize) {
+    let mut buf: [u8; 1] = [0; 1];
+    std::io::stdin().read_exact(&mut buf).expect("stdin read failed");
+    mem[*ptr] = buf[0];
+}
+
+// Execute a single COW opcode against the current state. This is the shared
+// dispatch path used both when running a flat instruction directly and when
+// ExecVal interprets a memory byte as an opcode. The loop delimiters (0/7) are
+// not handled here since they need the program counter; opcode 3 (ExecVal) and
+// any value >= 12 are no-ops.
+fn dispatch(opcode: u8, mem: &mut Vec<u8>, ptr: &mut usize, reg: &mut Register) {
+    match opcode {
+        // The pointer wraps modulo the tape length so walking off either end is
+        // well defined instead of panicking on a usize under/overflow.
+        1 => *ptr = (*ptr + mem.len() - 1) % mem.len(),
+        2 => *ptr = (*ptr + 1) % mem.len(),
+        4 => {
+            // If current memory block is 0, execute a Read; otherwise a Write.
+            if mem[*ptr] == 0 {
+                read_byte(mem, ptr);
+            } else {
+                write_byte(mem, ptr);
+            }
+        }
+        5 => mem[*ptr] = mem[*ptr].wrapping_sub(1u8),
+        6 => mem[*ptr] = mem[*ptr].wrapping_add(1u8),
+        8 => mem[*ptr] = 0,
+        9 => {
+            if reg.empty {
+                reg.value = mem[*ptr];
+            } else {
+                mem[*ptr] = reg.value;
+            }
+
+            reg.empty = !reg.empty;
+        }
+        10 => write_byte(mem, ptr),
+        11 => read_byte(mem, ptr),
+        _ => (),
+    }
+}
+
+// Execute the single instruction at `pc` and return the program counter of the
+// next instruction to run. Pulled out of the main loop so the debugger can
+// advance the machine one instruction at a time through the same code path.
+fn step(
+    program: &Vec<FlatInstruction>,
+    mem: &mut Vec<u8>,
+    ptr: &mut usize,
+    reg: &mut Register,
+    pc: usize,
+) -> usize {
+    match &program[pc] {
+        // If current memory block is 0, jump past the matching LoopEnd.
+        FlatInstruction::LoopStart(end) => {
+            if mem[*ptr] == 0 {
+                return *end + 1;
             }
-            Instruction::Write => print!("{}", mem[*ptr]),
-            Instruction::Read => {
-                // Read just one byte.
-                let mut buf: [u8; 1] = [0; 1];
-                std::io::stdin().read_exact(&mut buf).expect("stdin read failed");
-                mem[*ptr] = buf[0];
+        }
+        // If current memory block is non-zero, jump back to the matching LoopStart.
+        FlatInstruction::LoopEnd(start) => {
+            if mem[*ptr] != 0 {
+                return *start + 1;
             }
-            Instruction::Loop(loop_instructions) => 
-            while mem[*ptr] != 0 {
-                exec(loop_instructions, mem, ptr, reg);
+        }
+        // Interpret the current memory block as an opcode and dispatch it.
+        // Value 3 must be ignored, otherwise it would re-trigger ExecVal and
+        // recurse forever.
+        FlatInstruction::ExecVal => {
+            let val = mem[*ptr];
+            if val != 3 {
+                dispatch(val, mem, ptr, reg);
             }
         }
+        instr => dispatch(instr.opcode(), mem, ptr, reg),
     }
+    pc + 1
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
+fn exec(program: &Vec<FlatInstruction>, mem: &mut Vec<u8>, ptr: &mut usize, reg: &mut Register) {
+    let mut pc: usize = 0;
+    while pc < program.len() {
+        pc = step(program, mem, ptr, reg, pc);
+    }
+}
 
-    let file_contents = fs::read_to_string(file_path)
-        .expect("Unable to read file");
+// Serialize a flat instruction stream into the on-disk bytecode format: one
+// opcode byte per instruction, with the two loop delimiters each followed by a
+// 4-byte little-endian absolute jump target (a byte offset into the bytecode).
+// Targets are resolved by backpatching: opening a loop pushes the position of
+// its reserved target onto a stack, and closing it writes the end's backward
+// target while patching the start's forward target.
+fn compile(program: &Vec<FlatInstruction>) -> Vec<u8> {
+    let mut bytecode: Vec<u8> = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
 
-    let lexed = lex(file_contents);
+    for instr in program {
+        let op_offset = bytecode.len();
+        bytecode.push(instr.opcode());
+
+        match instr {
+            FlatInstruction::LoopStart(_) => {
+                // Remember where the forward target goes; patch it on LoopEnd.
+                stack.push(bytecode.len());
+                bytecode.extend_from_slice(&[0u8; 4]);
+            }
+            FlatInstruction::LoopEnd(_) => {
+                let start_target = stack.pop().expect("loop end with no matching start");
+                // Backward target: the opcode byte of the matching LoopStart.
+                let start_op = (start_target - 1) as u32;
+                bytecode.extend_from_slice(&start_op.to_le_bytes());
+                // Forward target: this LoopEnd's opcode byte.
+                let end_op = op_offset as u32;
+                bytecode[start_target..start_target + 4].copy_from_slice(&end_op.to_le_bytes());
+            }
+            _ => (),
+        }
+    }
+
+    bytecode
+}
+
+// Read a 4-byte little-endian jump target out of the bytecode at offset `i`.
+fn read_target(bytecode: &Vec<u8>, i: usize) -> u32 {
+    u32::from_le_bytes([bytecode[i], bytecode[i + 1], bytecode[i + 2], bytecode[i + 3]])
+}
+
+// Load the bytecode format back into a flat instruction stream ready to run.
+// Loop targets are stored as byte offsets, so a first pass records the
+// instruction index living at each offset and a second pass rewrites the loop
+// delimiters to carry instruction indices the executor can jump to.
+fn load(bytecode: &Vec<u8>) -> Vec<FlatInstruction> {
+    let mut program: Vec<FlatInstruction> = Vec::new();
+    let mut index_at_offset: HashMap<usize, usize> = HashMap::new();
+    let mut targets: Vec<Option<u32>> = Vec::new();
+
+    let mut i = 0;
+    while i < bytecode.len() {
+        index_at_offset.insert(i, program.len());
+
+        let op = bytecode[i];
+        i += 1;
+
+        let (instr, target) = match op {
+            0 => {
+                let t = read_target(bytecode, i);
+                i += 4;
+                (FlatInstruction::LoopEnd(0), Some(t))
+            }
+            1 => (FlatInstruction::DecPtr, None),
+            2 => (FlatInstruction::IncPtr, None),
+            3 => (FlatInstruction::ExecVal, None),
+            4 => (FlatInstruction::RWCond, None),
+            5 => (FlatInstruction::DecVal, None),
+            6 => (FlatInstruction::IncVal, None),
+            7 => {
+                let t = read_target(bytecode, i);
+                i += 4;
+                (FlatInstruction::LoopStart(0), Some(t))
+            }
+            8 => (FlatInstruction::ZeroVal, None),
+            9 => (FlatInstruction::RegAccess, None),
+            10 => (FlatInstruction::Write, None),
+            11 => (FlatInstruction::Read, None),
+            _ => panic!("invalid opcode byte {} in bytecode", op),
+        };
+
+        program.push(instr);
+        targets.push(target);
+    }
+
+    // Second pass: translate the stored byte offsets into instruction indices.
+    for idx in 0..program.len() {
+        if let Some(t) = targets[idx] {
+            let partner = *index_at_offset
+                .get(&(t as usize))
+                .expect("bad jump target in bytecode");
+            program[idx] = if program[idx].opcode() == 7 {
+                FlatInstruction::LoopStart(partner)
+            } else {
+                FlatInstruction::LoopEnd(partner)
+            };
+        }
+    }
+
+    program
+}
+
+// Lex, parse and lower a .cow source file into a flat instruction stream.
+fn compile_source(file_path: &str) -> Vec<FlatInstruction> {
+    let file_contents = fs::read_to_string(file_path).expect("Unable to read file");
 
+    let lexed = lex(file_contents);
     let instructions = parse(lexed);
 
-    // Allocate memory for use when executing
-    let mut mem: Vec<u8> = vec![0; MEM_SIZE];
+    let mut program: Vec<FlatInstruction> = Vec::new();
+    lower(&instructions, &mut program);
+    program
+}
+
+// Allocate a zeroed tape of the requested length, rejecting an empty or
+// oversized request.
+fn new_tape(tape_size: usize) -> Vec<u8> {
+    if tape_size == 0 || tape_size > MAX_TAPE_SIZE {
+        panic!("--tape-size must be between 1 and {}", MAX_TAPE_SIZE);
+    }
+
+    vec![0; tape_size]
+}
+
+// Execute a flat instruction stream on a freshly allocated tape.
+fn run(program: &Vec<FlatInstruction>, tape_size: usize) {
+    let mut mem: Vec<u8> = new_tape(tape_size);
     let mut ptr: usize = 0;
     let mut reg = Register {
         value: 0,
         empty: true,
     };
 
-    exec(&instructions, &mut mem, &mut ptr, &mut reg);
+    exec(program, &mut mem, &mut ptr, &mut reg);
+}
+
+// Print the current machine state: the instruction pointer and the instruction
+// it points at, the data pointer, a window of tape cells centred on it (the
+// current cell in brackets), and the register.
+fn print_state(program: &Vec<FlatInstruction>, mem: &Vec<u8>, ptr: usize, reg: &Register, pc: usize) {
+    if pc < program.len() {
+        println!("ip={}  {:?}", pc, program[pc]);
+    } else {
+        println!("ip={}  (halted)", pc);
+    }
+
+    let lo = ptr.saturating_sub(4);
+    let hi = std::cmp::min(ptr + 4, mem.len() - 1);
+    let mut cells = String::new();
+    for i in lo..=hi {
+        if i == ptr {
+            cells.push_str(&format!("[{}] ", mem[i]));
+        } else {
+            cells.push_str(&format!("{} ", mem[i]));
+        }
+    }
+    println!("dp={}  tape[{}..={}]: {}", ptr, lo, hi, cells.trim_end());
+
+    if reg.empty {
+        println!("reg: empty");
+    } else {
+        println!("reg: {}", reg.value);
+    }
+}
+
+// Interactive, instruction-stepping debugger over the flat instruction stream.
+// Because mOO/Moo make control flow value-dependent, halting at an instruction
+// and inspecting the tape is the only practical way to follow a real program.
+fn debug(program: &Vec<FlatInstruction>, tape_size: usize) {
+    let mut mem: Vec<u8> = new_tape(tape_size);
+    let mut ptr: usize = 0;
+    let mut reg = Register {
+        value: 0,
+        empty: true,
+    };
+    let mut pc: usize = 0;
+    let mut breakpoints: HashSet<usize> = HashSet::new();
+
+    println!("COW debugger. Type 'h' for a list of commands.");
+
+    loop {
+        print_state(program, &mem, ptr, &reg, pc);
+
+        print!("(cow) ");
+        std::io::stdout().flush().expect("stdout write failed");
+
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line).expect("stdin read failed") == 0 {
+            // EOF on the debugger prompt ends the session.
+            break;
+        }
+
+        let tokens = line.split_whitespace().collect::<Vec<&str>>();
+        let command = match tokens.first() {
+            Some(command) => *command,
+            None => continue,
+        };
+
+        match command {
+            "s" | "step" => {
+                if pc < program.len() {
+                    pc = step(program, &mut mem, &mut ptr, &mut reg, pc);
+                } else {
+                    println!("program has halted");
+                }
+            }
+            "c" | "continue" => {
+                if pc >= program.len() {
+                    println!("program has halted");
+                } else {
+                    // Always advance past the current instruction first so a
+                    // breakpoint on it doesn't stop us immediately.
+                    pc = step(program, &mut mem, &mut ptr, &mut reg, pc);
+                    while pc < program.len() && !breakpoints.contains(&pc) {
+                        pc = step(program, &mut mem, &mut ptr, &mut reg, pc);
+                    }
+                }
+            }
+            "b" | "break" => match tokens.get(1).and_then(|t| t.parse::<usize>().ok()) {
+                Some(index) => {
+                    breakpoints.insert(index);
+                    println!("breakpoint set at {}", index);
+                    if index >= program.len() {
+                        println!("warning: index is past the last instruction ({})", program.len());
+                    }
+                }
+                None => println!("usage: b <instruction index>"),
+            },
+            "d" | "delete" => match tokens.get(1).and_then(|t| t.parse::<usize>().ok()) {
+                Some(index) => {
+                    if breakpoints.remove(&index) {
+                        println!("breakpoint cleared at {}", index);
+                    } else {
+                        println!("no breakpoint at {}", index);
+                    }
+                }
+                None => println!("usage: d <instruction index>"),
+            },
+            "m" | "mem" => {
+                let lo = tokens.get(1).and_then(|t| t.parse::<usize>().ok());
+                let hi = tokens.get(2).and_then(|t| t.parse::<usize>().ok());
+                match (lo, hi) {
+                    (Some(lo), Some(hi)) if lo <= hi && hi < mem.len() => {
+                        for i in lo..=hi {
+                            println!("  [{}] = {}", i, mem[i]);
+                        }
+                    }
+                    _ => println!("usage: m <start> <end>"),
+                }
+            }
+            "q" | "quit" => break,
+            "h" | "help" => {
+                println!("s[tep]            execute one instruction");
+                println!("c[ontinue]        run until the next breakpoint or halt");
+                println!("b[reak] <index>   set a breakpoint at an instruction index");
+                println!("d[elete] <index>  clear a breakpoint");
+                println!("m[em] <lo> <hi>   dump tape cells lo..=hi");
+                println!("q[uit]            leave the debugger");
+            }
+            _ => println!("unknown command '{}'; type 'h' for help", command),
+        }
+    }
+}
+
+// Pull the first positional argument, an optional --tape-size, and the --debug
+// flag out of `args`.
+fn parse_run_args(args: &[String]) -> (String, usize, bool) {
+    let mut file_path: Option<String> = None;
+    let mut tape_size = MEM_SIZE;
+    let mut debug = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--tape-size" => {
+                let value = args.get(i + 1).expect("--tape-size requires a value");
+                tape_size = value.parse::<usize>().expect("--tape-size must be a number");
+                i += 2;
+            }
+            "--debug" => {
+                debug = true;
+                i += 1;
+            }
+            _ => {
+                // First non-flag argument is the file; later ones are ignored.
+                if file_path.is_none() {
+                    file_path = Some(args[i].clone());
+                }
+                i += 1;
+            }
+        }
+    }
+
+    (file_path.expect("No source file given"), tape_size, debug)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(|s| s.as_str()) {
+        // cower compile prog.cow -o prog.cowbc
+        Some("compile") => {
+            let rest = &args[2..];
+            let o_pos = rest
+                .iter()
+                .position(|a| a == "-o")
+                .expect("compile requires -o <output>");
+            let output = rest.get(o_pos + 1).expect("compile requires -o <output>");
+            // The source file is the positional argument that isn't -o or its value.
+            let input = rest
+                .iter()
+                .enumerate()
+                .find(|(idx, _)| *idx != o_pos && *idx != o_pos + 1)
+                .map(|(_, a)| a.as_str())
+                .expect("No source file given");
+
+            let program = compile_source(input);
+            let bytecode = compile(&program);
+            fs::write(output, bytecode).expect("Unable to write bytecode");
+        }
+        // cower run prog.cowbc [--tape-size N] [--debug]
+        Some("run") => {
+            let (file_path, tape_size, debug_mode) = parse_run_args(&args[2..]);
+            let bytecode = fs::read(&file_path).expect("Unable to read file");
+            let program = load(&bytecode);
+            if debug_mode {
+                debug(&program, tape_size);
+            } else {
+                run(&program, tape_size);
+            }
+        }
+        // Legacy form: cower prog.cow [--tape-size N] [--debug] runs source directly.
+        _ => {
+            let (file_path, tape_size, debug_mode) = parse_run_args(&args[1..]);
+            let program = compile_source(&file_path);
+            if debug_mode {
+                debug(&program, tape_size);
+            } else {
+                run(&program, tape_size);
+            }
+        }
+    }
 }